@@ -8,6 +8,7 @@ mod owned;
 mod stack;
 mod commute;
 
+use std::collections::HashMap;
 use std::io::Write;
 use stack::WorkingStackOptions;
 
@@ -16,9 +17,49 @@ pub struct Config<'a> {
     pub force: bool,
     pub base: Option<&'a str>,
     pub logger: &'a slog::Logger,
+    /// instead of emitting one `fixup!` commit per absorbed hunk,
+    /// coalesce every hunk destined for the same commit into a
+    /// single fixup, applied cumulatively to that commit's tree
+    pub one_fixup_per_commit: bool,
+    /// after absorbing, fold the generated `fixup!` commits into
+    /// their targets with an autosquash rebase, leaving no trace of
+    /// them in the history; if the rebase fails (e.g. on a conflict),
+    /// a warning is logged and the fixups are left in place
+    pub and_rebase: bool,
+    /// a fallback for hunks the commute algorithm can't place: when a
+    /// hunk commutes cleanly with every commit in the stack (so there
+    /// is no noncommutative destination), absorb it into the newest
+    /// commit that merely touches its file instead of dropping it.
+    /// this only kicks in once commutation has already failed to find
+    /// a destination — it does not override a noncommutative result
+    /// commutation *did* find, since that result is more precisely
+    /// placed than "newest commit touching this file"
+    pub whole_file: bool,
+    /// pick each hunk's destination by blaming the lines it removes
+    /// or modifies, overriding the commute result; hunks that remove
+    /// no lines, or whose removed lines blame to no commit in the
+    /// stack, keep the destination commutation (or `whole_file`) found
+    pub blame: bool,
+    /// in dry-run mode, emit the absorption plan in this format to
+    /// `out` instead of only logging it
+    pub plan_format: PlanFormat,
 }
 
-pub fn run(config: &Config) -> Result<(), failure::Error> {
+/// how `run` reports the dry-run plan, for callers (editor/TUI
+/// integrations) that want to preview and approve absorptions before
+/// they're applied
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    /// log plan lines through `config.logger`, as before
+    None,
+    /// one JSON record per absorbed hunk, written to `out`
+    Json,
+    /// a unified-diff-style patch per destination commit, written to
+    /// `out`
+    Patch,
+}
+
+pub fn run(config: &Config, out: &mut dyn Write) -> Result<(), failure::Error> {
     let repo = git2::Repository::open_from_env()?;
     debug!(config.logger, "repository found"; "path" => repo.path().to_str());
 
@@ -69,6 +110,50 @@ pub fn run(config: &Config) -> Result<(), failure::Error> {
 
     let signature = repo.signature()?;
     let mut head_commit = repo.head()?.peel_to_commit()?;
+    // the commit blame should treat as HEAD: captured once, before the
+    // loop below starts advancing `head_commit` with each committed
+    // fixup, so blame always sees the tree absorption started from
+    let original_head_id = head_commit.id();
+    // the oldest commit blame is allowed to attribute a line to,
+    // whether or not the user passed an explicit `base`, so lines
+    // below the working stack never get attributed to a commit outside
+    // it; mirrors the "onto" fallback used for the autosquash rebase
+    let stack_floor = match base {
+        Some(ref commit) => commit.clone(),
+        None => match stack.last() {
+            Some(&(ref oldest, _)) if oldest.parents().len() > 0 => oldest.parent(0)?,
+            Some(&(ref oldest, _)) => oldest.clone(),
+            None => head_commit.clone(),
+        },
+    };
+    let mut any_fixup_committed = false;
+
+    // when `one_fixup_per_commit` is set, hunks are not committed as
+    // they're absorbed; instead each destination commit accumulates
+    // its hunks here, keyed by destination id, and the fixups are
+    // emitted once both loops below are done by threading a single
+    // evolving tree through the destinations in order, so each
+    // fixup's parent tree already contains every earlier
+    // destination's edits and its own diff is exactly its own hunks
+    let mut pending_hunks: HashMap<git2::Oid, Vec<(&[u8], &owned::Hunk)>> = HashMap::new();
+    let mut pending_order: Vec<git2::Oid> = Vec::new();
+    // a hunk's anchors are offsets into the blob as it stands in the
+    // tree at the moment it's applied, so hunks within a single file
+    // must be applied in the order they appear in the file; grouping
+    // by destination preserves that whenever a file's hunks all share
+    // one destination, but if two hunks in the same file target
+    // different destinations, applying them in destination order
+    // (rather than file order) would read stale anchors against a
+    // blob the other destination's fixup already rewrote, so that
+    // split is tracked here and rejected below
+    let mut file_destination: HashMap<Vec<u8>, git2::Oid> = HashMap::new();
+
+    // when `plan_format` is `Patch`, hunks are grouped by destination
+    // commit here so they can be emitted as one patch per commit once
+    // the loop below finishes; `Json` needs no such grouping, since
+    // each hunk is its own record, and is written as it's absorbed
+    let mut planned: HashMap<git2::Oid, (String, Vec<PlannedHunk<'_>>)> = HashMap::new();
+    let mut planned_order: Vec<git2::Oid> = Vec::new();
 
     'patch: for index_patch in index.iter() {
         'hunk: for index_hunk in &index_patch.hunks {
@@ -92,8 +177,11 @@ pub fn run(config: &Config) -> Result<(), failure::Error> {
             );
 
             // find the newest commit that the hunk cannot commute
-            // with
+            // with, tracking along the way the newest commit that
+            // merely touches the hunk's file, for the `whole_file`
+            // fallback below
             let mut dest_commit = None;
+            let mut whole_file_candidate = None;
             'commit: for &(ref commit, ref diff) in &stack {
                 let c_logger = config.logger.new(o!(
                     "commit" => commit.id().to_string(),
@@ -108,6 +196,9 @@ pub fn run(config: &Config) -> Result<(), failure::Error> {
                         continue 'commit;
                     }
                 };
+                if whole_file_candidate.is_none() {
+                    whole_file_candidate = Some(commit);
+                }
                 if next_patch.status == git2::Delta::Added {
                     debug!(c_logger, "found noncommutative commit by add");
                     dest_commit = Some(commit);
@@ -138,6 +229,33 @@ pub fn run(config: &Config) -> Result<(), failure::Error> {
                     }
                 };
             }
+            let dest_commit = dest_commit.or_else(|| {
+                if config.whole_file {
+                    whole_file_candidate
+                } else {
+                    None
+                }
+            });
+
+            // blame is an alternative to the commute algorithm above:
+            // for hunks that remove or modify existing lines, trust
+            // whoever last touched those lines over the commute
+            // result, which can mis-place edits when several commits
+            // touch nearby but non-overlapping regions
+            let dest_commit = if config.blame {
+                blame_destination(
+                    &repo,
+                    &stack,
+                    original_head_id,
+                    stack_floor.id(),
+                    index_patch.old_path.as_slice(),
+                    index_hunk.removed.start,
+                    index_hunk.removed.lines.len(),
+                )?.or(dest_commit)
+            } else {
+                dest_commit
+            };
+
             let dest_commit = match dest_commit {
                 Some(commit) => commit,
                 // the hunk commutes with every commit in the stack,
@@ -149,37 +267,391 @@ pub fn run(config: &Config) -> Result<(), failure::Error> {
             };
 
             if !config.dry_run {
-                head_tree =
-                    apply_hunk_to_tree(&repo, &head_tree, index_hunk, &index_patch.old_path)?;
-                head_commit = repo.find_commit(repo.commit(
-                    Some("HEAD"),
-                    &signature,
-                    &signature,
-                    &format!("fixup! {} {}", dest_commit.id(),
-                        dest_commit.summary().unwrap_or("<no message>")),
-                    &head_tree,
-                    &[&head_commit],
-                )?)?;
-                info!(config.logger, "committed";
-                      "commit" => head_commit.id().to_string(),
-                );
+                if config.one_fixup_per_commit {
+                    let dest_id = dest_commit.id();
+                    check_single_destination(
+                        &mut file_destination,
+                        index_patch.old_path.as_slice(),
+                        dest_id,
+                    )?;
+                    let is_new = !pending_hunks.contains_key(&dest_id);
+                    pending_hunks
+                        .entry(dest_id)
+                        .or_insert_with(Vec::new)
+                        .push((index_patch.old_path.as_slice(), index_hunk));
+                    if is_new {
+                        pending_order.push(dest_id);
+                    }
+                    debug!(config.logger, "queued hunk for coalesced fixup";
+                           "fixup" => dest_id.to_string(),
+                    );
+                } else {
+                    head_tree =
+                        apply_hunk_to_tree(&repo, &head_tree, index_hunk, &index_patch.old_path)?;
+                    head_commit = repo.find_commit(repo.commit(
+                        Some("HEAD"),
+                        &signature,
+                        &signature,
+                        &format!("fixup! {} {}", dest_commit.id(),
+                            dest_commit.summary().unwrap_or("<no message>")),
+                        &head_tree,
+                        &[&head_commit],
+                    )?)?;
+                    any_fixup_committed = true;
+                    info!(config.logger, "committed";
+                          "commit" => head_commit.id().to_string(),
+                    );
+                }
             } else {
-                info!(config.logger, "would have committed";
-                      "fixup" => dest_commit.id().to_string(),
-                      "header" => format!("-{},{} +{},{}",
-                                          index_hunk.removed.start,
-                                          index_hunk.removed.lines.len(),
-                                          index_hunk.added.start,
-                                          index_hunk.added.lines.len(),
-                      ),
+                match config.plan_format {
+                    PlanFormat::None => {
+                        info!(config.logger, "would have committed";
+                              "fixup" => dest_commit.id().to_string(),
+                              "header" => format!("-{},{} +{},{}",
+                                                  index_hunk.removed.start,
+                                                  index_hunk.removed.lines.len(),
+                                                  index_hunk.added.start,
+                                                  index_hunk.added.lines.len(),
+                              ),
+                        );
+                    }
+                    PlanFormat::Json => {
+                        writeln!(
+                            out,
+                            "{}",
+                            plan_json_line(dest_commit, &index_patch.old_path, index_hunk)
+                        )?;
+                    }
+                    PlanFormat::Patch => {
+                        let dest_id = dest_commit.id();
+                        let is_new = !planned.contains_key(&dest_id);
+                        planned
+                            .entry(dest_id)
+                            .or_insert_with(|| {
+                                (dest_commit.summary().unwrap_or("<no message>").to_string(), Vec::new())
+                            })
+                            .1
+                            .push(PlannedHunk {
+                                path: index_patch.old_path.as_slice(),
+                                hunk: index_hunk,
+                            });
+                        if is_new {
+                            planned_order.push(dest_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if config.one_fixup_per_commit {
+        for dest_id in pending_order {
+            let hunks = pending_hunks
+                .remove(&dest_id)
+                .expect("pending_order and pending_hunks must stay in sync");
+            for (path, hunk) in hunks {
+                head_tree = apply_hunk_to_tree(&repo, &head_tree, hunk, path)?;
+            }
+            let dest_commit = repo.find_commit(dest_id)?;
+            head_commit = repo.find_commit(repo.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                &format!("fixup! {} {}", dest_commit.id(),
+                    dest_commit.summary().unwrap_or("<no message>")),
+                &head_tree,
+                &[&head_commit],
+            )?)?;
+            any_fixup_committed = true;
+            info!(config.logger, "committed";
+                  "commit" => head_commit.id().to_string(),
+            );
+        }
+    }
+
+    if config.plan_format == PlanFormat::Patch {
+        for dest_id in planned_order {
+            let (summary, hunks) = planned
+                .remove(&dest_id)
+                .expect("planned_order and planned must stay in sync");
+            writeln!(out, "fixup! {} {}", dest_id, summary)?;
+
+            // group by file so each file gets exactly one `--- a/
+            // +++ b/` header, with all its hunks listed underneath,
+            // as a valid, applyable unified diff expects
+            let mut by_path: Vec<(&[u8], Vec<&owned::Hunk>)> = Vec::new();
+            for planned_hunk in &hunks {
+                match by_path.iter_mut().find(|&&mut (path, _)| path == planned_hunk.path) {
+                    Some(entry) => entry.1.push(planned_hunk.hunk),
+                    None => by_path.push((planned_hunk.path, vec![planned_hunk.hunk])),
+                }
+            }
+
+            for (path, file_hunks) in by_path {
+                let path_str = String::from_utf8_lossy(path);
+                writeln!(out, "--- a/{}", path_str)?;
+                writeln!(out, "+++ b/{}", path_str)?;
+                for hunk in file_hunks {
+                    let (removed_start, removed_len) = unified_hunk_range(hunk.removed.start, hunk.removed.lines.len());
+                    let (added_start, added_len) = unified_hunk_range(hunk.added.start, hunk.added.lines.len());
+                    writeln!(out, "@@ -{},{} +{},{} @@", removed_start, removed_len, added_start, added_len)?;
+                    for line in &*hunk.removed.lines {
+                        out.write_all(b"-")?;
+                        out.write_all(line)?;
+                    }
+                    for line in &*hunk.added.lines {
+                        out.write_all(b"+")?;
+                        out.write_all(line)?;
+                    }
+                }
+            }
+        }
+    }
+
+    if config.and_rebase && !config.dry_run && any_fixup_committed {
+        match autosquash_rebase(&repo, &stack_floor, &head_commit, config.logger) {
+            Ok(new_tip) => {
+                repo.reset(new_tip.as_object(), git2::ResetType::Hard, None)?;
+                info!(config.logger, "rebased with autosquash";
+                      "commit" => new_tip.id().to_string(),
                 );
             }
+            Err(e) => warn!(config.logger, "autosquash rebase failed, leaving fixups in place";
+                             "error" => e.to_string(),
+            ),
         }
+    } else if config.and_rebase && !config.dry_run {
+        debug!(config.logger, "nothing absorbed, skipping autosquash rebase");
     }
 
     Ok(())
 }
 
+/// fold every `fixup! <id> <summary>` commit between `onto` and
+/// `head_commit` into the commit it targets, preserving that
+/// commit's author, committer, and message, and return the new tip.
+/// this is a manual, non-interactive stand-in for `git rebase -i
+/// --autosquash`, since git2 has no direct equivalent.
+fn autosquash_rebase<'repo>(
+    repo: &'repo git2::Repository,
+    onto: &git2::Commit<'repo>,
+    head_commit: &git2::Commit<'repo>,
+    logger: &slog::Logger,
+) -> Result<git2::Commit<'repo>, failure::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head_commit.id())?;
+    revwalk.hide(onto.id())?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+    // split the stack into the commits it's made of and the fixups
+    // that target them, preserving the order each was authored in
+    let mut targets = Vec::new();
+    let mut fixups: HashMap<git2::Oid, Vec<git2::Commit>> = HashMap::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        match parse_fixup_target(commit.message().unwrap_or("")) {
+            Some(target_id) => fixups.entry(target_id).or_insert_with(Vec::new).push(commit),
+            None => targets.push(commit),
+        }
+    }
+
+    let mut new_tip = onto.clone();
+    for target in &targets {
+        let index = repo.cherrypick_commit(target, &new_tip, 0, None)?;
+        if index.has_conflicts() {
+            return Err(failure::err_msg(format!(
+                "conflict rebasing {} onto {}",
+                target.id(), new_tip.id(),
+            )));
+        }
+        let mut squashed = repo.find_commit(repo.commit(
+            None,
+            &target.author(),
+            &target.committer(),
+            target.message().unwrap_or("<no message>"),
+            &repo.find_tree(index.write_tree_to(repo)?)?,
+            &[&new_tip],
+        )?)?;
+
+        for fixup in fixups.remove(&target.id()).unwrap_or_default() {
+            let index = repo.cherrypick_commit(&fixup, &squashed, 0, None)?;
+            if index.has_conflicts() {
+                return Err(failure::err_msg(format!(
+                    "conflict folding fixup {} into {}",
+                    fixup.id(), target.id(),
+                )));
+            }
+            squashed = repo.find_commit(repo.commit(
+                None,
+                &target.author(),
+                &target.committer(),
+                target.message().unwrap_or("<no message>"),
+                &repo.find_tree(index.write_tree_to(repo)?)?,
+                &[&new_tip],
+            )?)?;
+            debug!(logger, "folded fixup into target";
+                   "fixup" => fixup.id().to_string(),
+                   "target" => target.id().to_string(),
+            );
+        }
+
+        new_tip = squashed;
+    }
+
+    if !fixups.is_empty() {
+        return Err(failure::err_msg(format!(
+            "{} fixup commit(s) target a commit outside the rebased range: {}",
+            fixups.values().map(Vec::len).sum::<usize>(),
+            fixups.keys().map(|oid| oid.to_string()).collect::<Vec<_>>().join(", "),
+        )));
+    }
+
+    Ok(new_tip)
+}
+
+/// record that `path`'s hunks are being coalesced into `dest_id`, and
+/// error out if an earlier hunk for the same path was already recorded
+/// against a different destination. `one_fixup_per_commit` applies
+/// queued hunks grouped by destination rather than in file order, so a
+/// file split across more than one destination would have its hunks
+/// applied out of order, corrupting the later hunks' line anchors;
+/// bailing here is safer than producing a silently wrong tree.
+fn check_single_destination(
+    file_destination: &mut HashMap<Vec<u8>, git2::Oid>,
+    path: &[u8],
+    dest_id: git2::Oid,
+) -> Result<(), failure::Error> {
+    match file_destination.get(path) {
+        Some(&existing) if existing != dest_id => Err(failure::err_msg(format!(
+            "cannot coalesce fixups: {} has hunks absorbed into both {} and {}, and \
+             one-fixup-per-commit can't split a single file's hunks across more than \
+             one destination commit",
+            String::from_utf8_lossy(path),
+            existing,
+            dest_id,
+        ))),
+        _ => {
+            file_destination.insert(path.to_vec(), dest_id);
+            Ok(())
+        }
+    }
+}
+
+/// parse the target commit id out of a `fixup! <id> ...` message, as
+/// produced by `run`'s own fixup commits
+fn parse_fixup_target(message: &str) -> Option<git2::Oid> {
+    const PREFIX: &str = "fixup! ";
+    if !message.starts_with(PREFIX) {
+        return None;
+    }
+    let id_str = message[PREFIX.len()..].split_whitespace().next()?;
+    git2::Oid::from_str(id_str).ok()
+}
+
+/// find the newest commit in `stack` that `git2::blame` attributes
+/// the hunk's removed lines to, restricting the blame walk to the
+/// working stack's range (`oldest_commit` to `newest_commit`) so it
+/// can't attribute a line to history outside the stack, or to a fixup
+/// this run has already committed. returns `None` for pure insertions
+/// (nothing to blame) or when blame finds no in-stack owner, in which
+/// case the caller should fall back to the commute result.
+fn blame_destination<'repo>(
+    repo: &'repo git2::Repository,
+    stack: &'repo [(git2::Commit<'repo>, owned::Diff)],
+    newest_commit: git2::Oid,
+    oldest_commit: git2::Oid,
+    old_path: &[u8],
+    removed_start: usize,
+    removed_len: usize,
+) -> Result<Option<&'repo git2::Commit<'repo>>, failure::Error> {
+    if removed_len == 0 {
+        return Ok(None);
+    }
+
+    let path_str = String::from_utf8_lossy(old_path).into_owned();
+    let mut blame_options = git2::BlameOptions::new();
+    // pin blame to the tree absorption started from: `head_commit`
+    // advances with every fixup this run commits, but `removed_start`
+    // is a line number in the original, pre-absorption tree
+    blame_options.newest_commit(newest_commit);
+    blame_options.oldest_commit(oldest_commit);
+    let blame = repo.blame_file(std::path::Path::new(&path_str), Some(&mut blame_options))?;
+
+    let mut attributed_to = std::collections::HashSet::new();
+    for line in 0..removed_len {
+        // git2's blame line numbers are 1-based, but `removed.start`
+        // (like the rest of this file's hunk anchors) is 0-based, so
+        // it needs a +1 to land on the first removed line
+        if let Some(blame_hunk) = blame.get_line(removed_start + 1 + line) {
+            attributed_to.insert(blame_hunk.final_commit_id());
+        }
+    }
+
+    // stack is ordered newest-first, so the first match is the most
+    // recent in-stack commit that last touched these lines
+    Ok(stack
+        .iter()
+        .find(|&&(ref commit, _)| attributed_to.contains(&commit.id()))
+        .map(|&(ref commit, _)| commit))
+}
+
+/// one hunk queued for the `Patch` plan format, grouped by
+/// destination commit once `run`'s absorb pass finishes
+struct PlannedHunk<'a> {
+    path: &'a [u8],
+    hunk: &'a owned::Hunk,
+}
+
+/// render a single absorbed hunk as one JSON record, for the `Json`
+/// plan format; hand-rolled rather than pulling in a serializer, since
+/// the record shape is small and fixed
+/// `removed`/`added` use the same 1-based `start,lines` convention as
+/// the `Patch` plan format's `@@` headers (see `unified_hunk_range`),
+/// so the two formats agree on what a given hunk's anchor means.
+fn plan_json_line(dest_commit: &git2::Commit, path: &[u8], hunk: &owned::Hunk) -> String {
+    let (removed_start, removed_lines) =
+        unified_hunk_range(hunk.removed.start, hunk.removed.lines.len());
+    let (added_start, added_lines) = unified_hunk_range(hunk.added.start, hunk.added.lines.len());
+    format!(
+        "{{\"commit\":\"{}\",\"summary\":\"{}\",\"path\":\"{}\",\
+         \"removed\":{{\"start\":{},\"lines\":{}}},\
+         \"added\":{{\"start\":{},\"lines\":{}}}}}",
+        dest_commit.id(),
+        json_escape(dest_commit.summary().unwrap_or("<no message>")),
+        json_escape(&String::from_utf8_lossy(path)),
+        removed_start, removed_lines,
+        added_start, added_lines,
+    )
+}
+
+/// convert a hunk's 0-based anchor and line count into the 1-based
+/// `start,count` pair a unified-diff `@@` header expects; a
+/// zero-length side (pure insertion or pure deletion) is conventionally
+/// anchored to the line before it, which is exactly the 0-based start
+fn unified_hunk_range(start: usize, len: usize) -> (usize, usize) {
+    if len == 0 {
+        (start, 0)
+    } else {
+        (start + 1, len)
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 fn apply_hunk_to_tree<'repo>(
     repo: &'repo git2::Repository,
     base: &git2::Tree,
@@ -260,3 +732,213 @@ fn skip_past_nth(needle: u8, haystack: &[u8], n: usize) -> usize {
         .map(|x| x + 1)
         .unwrap_or(haystack.len())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fixup_target_reads_the_oid_out_of_a_fixup_message() {
+        let oid = git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let message = format!("fixup! {} some subject line", oid);
+        assert_eq!(parse_fixup_target(&message), Some(oid));
+    }
+
+    #[test]
+    fn parse_fixup_target_ignores_messages_without_the_prefix() {
+        assert_eq!(parse_fixup_target("some subject line"), None);
+    }
+
+    #[test]
+    fn parse_fixup_target_ignores_a_malformed_oid() {
+        assert_eq!(parse_fixup_target("fixup! not-an-oid some subject line"), None);
+    }
+
+    #[test]
+    fn unified_hunk_range_shifts_a_nonempty_side_to_1_based() {
+        assert_eq!(unified_hunk_range(0, 3), (1, 3));
+        assert_eq!(unified_hunk_range(41, 1), (42, 1));
+    }
+
+    #[test]
+    fn unified_hunk_range_anchors_a_zero_length_side_to_the_preceding_line() {
+        assert_eq!(unified_hunk_range(5, 0), (5, 0));
+    }
+
+    #[test]
+    fn json_escape_escapes_control_and_quote_characters() {
+        assert_eq!(json_escape("a\"b\\c\nd\te"), "a\\\"b\\\\c\\nd\\te");
+        assert_eq!(json_escape("plain"), "plain");
+    }
+
+    #[test]
+    fn json_escape_escapes_other_control_characters_as_unicode_points() {
+        assert_eq!(json_escape("a\u{7}b"), "a\\u0007b");
+    }
+
+    /// a directory under the system temp dir that's removed on drop;
+    /// std alone has no equivalent, and this crate has no dev-only
+    /// dependency on a crate that does
+    struct ScratchDir(std::path::PathBuf);
+
+    impl ScratchDir {
+        fn new() -> Self {
+            static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+            let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!(
+                "git-absorb-test-{}-{}",
+                std::process::id(),
+                n,
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            ScratchDir(path)
+        }
+
+        fn path(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn init_repo() -> (ScratchDir, git2::Repository) {
+        let dir = ScratchDir::new();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        (dir, repo)
+    }
+
+    fn commit_file(
+        repo: &git2::Repository,
+        parent: Option<&git2::Commit>,
+        path: &str,
+        content: &[u8],
+        message: &str,
+    ) -> git2::Oid {
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        std::fs::write(repo.path().parent().unwrap().join(path), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(std::path::Path::new(path)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parents: Vec<&git2::Commit> = parent.into_iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn blame_destination_picks_the_newest_stack_commit_that_touched_the_removed_lines() {
+        let (_dir, repo) = init_repo();
+
+        let base_id = commit_file(&repo, None, "file", b"base\n", "base");
+        let base_commit = repo.find_commit(base_id).unwrap();
+
+        let older_id = commit_file(
+            &repo,
+            Some(&base_commit),
+            "file",
+            b"base\nolder\n",
+            "older",
+        );
+        let older_commit = repo.find_commit(older_id).unwrap();
+
+        let newer_id = commit_file(
+            &repo,
+            Some(&older_commit),
+            "file",
+            b"base\nolder\nnewer\n",
+            "newer",
+        );
+        let newer_commit = repo.find_commit(newer_id).unwrap();
+
+        // blame_destination only needs the commits' ids to pick a
+        // destination, so the per-commit diffs threaded alongside them
+        // in `stack` can be empty placeholders here
+        let empty_diff = || {
+            owned::Diff::new(
+                &repo
+                    .diff_tree_to_tree(
+                        Some(&base_commit.tree().unwrap()),
+                        Some(&base_commit.tree().unwrap()),
+                        None,
+                    )
+                    .unwrap(),
+            )
+            .unwrap()
+        };
+        let stack = vec![
+            (newer_commit.clone(), empty_diff()),
+            (older_commit.clone(), empty_diff()),
+        ];
+
+        // line 2 ("newer") was introduced by `newer_commit`
+        let dest = blame_destination(
+            &repo,
+            &stack,
+            newer_id,
+            base_id,
+            b"file",
+            2,
+            1,
+        )
+        .unwrap();
+        assert_eq!(dest.map(git2::Commit::id), Some(newer_id));
+    }
+
+    #[test]
+    fn check_single_destination_accepts_repeat_hunks_for_the_same_destination() {
+        let mut file_destination = HashMap::new();
+        let dest = git2::Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+        check_single_destination(&mut file_destination, b"file", dest).unwrap();
+        check_single_destination(&mut file_destination, b"file", dest).unwrap();
+    }
+
+    #[test]
+    fn check_single_destination_accepts_different_files_with_different_destinations() {
+        let mut file_destination = HashMap::new();
+        let a = git2::Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+        let b = git2::Oid::from_str("2222222222222222222222222222222222222222").unwrap();
+        check_single_destination(&mut file_destination, b"file-a", a).unwrap();
+        check_single_destination(&mut file_destination, b"file-b", b).unwrap();
+    }
+
+    #[test]
+    fn check_single_destination_rejects_a_file_split_across_destinations() {
+        // pins the `one_fixup_per_commit` ordering invariant: a file
+        // whose hunks target interleaved destinations (h1@10->A,
+        // h2@20->B, h3@30->A) can't be coalesced without reordering
+        // hunks out of file order, so the second destination seen for
+        // the same file must be rejected rather than silently applied
+        let mut file_destination = HashMap::new();
+        let a = git2::Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+        let b = git2::Oid::from_str("2222222222222222222222222222222222222222").unwrap();
+        check_single_destination(&mut file_destination, b"file", a).unwrap();
+        assert!(check_single_destination(&mut file_destination, b"file", b).is_err());
+    }
+
+    #[test]
+    fn plan_json_line_emits_1_based_ranges_matching_the_patch_format() {
+        let (_dir, repo) = init_repo();
+        let commit_id = commit_file(&repo, None, "file", b"base\n", "a summary");
+        let commit = repo.find_commit(commit_id).unwrap();
+
+        let hunk = owned::Hunk {
+            removed: owned::Side {
+                start: 4,
+                lines: vec![b"old\n".to_vec()],
+            },
+            added: owned::Side {
+                start: 4,
+                lines: vec![b"new\n".to_vec()],
+            },
+        };
+
+        let line = plan_json_line(&commit, b"file", &hunk);
+        assert!(line.contains(&format!("\"commit\":\"{}\"", commit_id)));
+        assert!(line.contains("\"removed\":{\"start\":5,\"lines\":1}"));
+        assert!(line.contains("\"added\":{\"start\":5,\"lines\":1}"));
+    }
+}